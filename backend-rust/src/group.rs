@@ -0,0 +1,51 @@
+//! Family-group membership
+//!
+//! A group is just a `group_id` string tying several `client_identifier`s together.
+//! Membership is stored in the `GROUPS` KV two ways: a forward index
+//! (`member:{client_identifier} -> group_id`) so a member's own group can be resolved,
+//! and a reverse index (`group:{group_id}:member:{client_identifier}`) so the full
+//! member list can be enumerated with a prefix scan.
+
+use worker::*;
+
+fn member_key(client_identifier: &str) -> String {
+    format!("member:{}", client_identifier)
+}
+
+fn group_member_key(group_id: &str, client_identifier: &str) -> String {
+    format!("group:{}:member:{}", group_id, client_identifier)
+}
+
+fn group_member_prefix(group_id: &str) -> String {
+    format!("group:{}:member:", group_id)
+}
+
+/// Record `client_identifier` as a member of `group_id`.
+pub async fn add_member(env: &Env, group_id: &str, client_identifier: &str) -> Result<()> {
+    let groups = env.kv("GROUPS")?;
+    groups.put(&member_key(client_identifier), group_id)?.execute().await?;
+    groups
+        .put(&group_member_key(group_id, client_identifier), "1")?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// The group `client_identifier` belongs to, if any.
+pub async fn member_group(env: &Env, client_identifier: &str) -> Result<Option<String>> {
+    let groups = env.kv("GROUPS")?;
+    groups.get(&member_key(client_identifier)).text().await
+}
+
+/// All members of `group_id`.
+pub async fn list_members(env: &Env, group_id: &str) -> Result<Vec<String>> {
+    let groups = env.kv("GROUPS")?;
+    let prefix = group_member_prefix(group_id);
+    let list = groups.list().prefix(prefix.clone()).execute().await?;
+
+    Ok(list
+        .keys
+        .into_iter()
+        .filter_map(|k| k.name.strip_prefix(&prefix).map(str::to_string))
+        .collect())
+}
@@ -0,0 +1,176 @@
+//! Family-group invitation tokens
+//!
+//! An authenticated group member mints a single-use invite token for a prospective
+//! relative. The token is self-contained (`base64url(payload) || "." || base64url(HMAC-
+//! SHA256(invite_key, payload))`) so it can be verified offline, but redemption is
+//! still gated by a one-shot counter so a captured token can't be replayed twice.
+//!
+//! Primary path: a Cloudflare Durable Object (one instance per invite token id) holds
+//! the `used` flag in its transactional storage and flips it inside a single `fetch`
+//! handler, so the check-then-mark is atomic per token - the same pattern `rate_limit`
+//! uses for its counters. Falls back to the `INVITES` KV when the `INVITE_GATE`
+//! Durable Object binding isn't configured (e.g. local `wrangler dev` without DOs set
+//! up); the KV fallback is a get-then-put and admits a double-redeem race under
+//! concurrent requests for the same token.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use worker::*;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long a minted invite remains redeemable.
+pub const INVITE_TTL_SECONDS: u64 = 60 * 60 * 24 * 7; // 7 days
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InvitePayload {
+    pub token_id: String,
+    pub group_id: String,
+    pub inviter: String,
+    pub exp: u64, // unix seconds
+}
+
+#[derive(Serialize, Deserialize)]
+struct InviteRecord {
+    used: bool,
+}
+
+fn storage_key(token_id: &str) -> String {
+    format!("invite:{}", token_id)
+}
+
+/// Mint a signed, single-use invite token for `group_id` and record it as unused.
+pub async fn create_invite(env: &Env, invite_key: &[u8], group_id: &str, inviter: &str) -> Result<String> {
+    let mut token_id_bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut token_id_bytes);
+
+    let payload = InvitePayload {
+        token_id: BASE64URL.encode(token_id_bytes),
+        group_id: group_id.to_string(),
+        inviter: inviter.to_string(),
+        exp: (Date::now().as_millis() / 1000) + INVITE_TTL_SECONDS,
+    };
+    let payload_b64 = BASE64URL.encode(serde_json::to_vec(&payload)?);
+
+    let mut mac = HmacSha256::new_from_slice(invite_key).map_err(|e| Error::from(e.to_string()))?;
+    mac.update(payload_b64.as_bytes());
+    let signature_b64 = BASE64URL.encode(mac.finalize().into_bytes());
+
+    let invites = env.kv("INVITES")?;
+    invites
+        .put(&storage_key(&payload.token_id), serde_json::to_string(&InviteRecord { used: false })?)?
+        .expiration_ttl(INVITE_TTL_SECONDS)
+        .execute()
+        .await?;
+
+    Ok(format!("{}.{}", payload_b64, signature_b64))
+}
+
+/// Verify a token's HMAC in constant time and return its payload. Does not check
+/// expiry or prior consumption - callers do that via `consume_invite`.
+pub fn verify_token(invite_key: &[u8], token: &str) -> Option<InvitePayload> {
+    let (payload_b64, signature_b64) = token.split_once('.')?;
+
+    let mut mac = HmacSha256::new_from_slice(invite_key).ok()?;
+    mac.update(payload_b64.as_bytes());
+    let expected_signature = mac.finalize().into_bytes();
+
+    let given_signature = BASE64URL.decode(signature_b64).ok()?;
+    if expected_signature.as_slice().ct_eq(&given_signature).unwrap_u8() != 1 {
+        return None;
+    }
+
+    let payload_json = BASE64URL.decode(payload_b64).ok()?;
+    serde_json::from_slice(&payload_json).ok()
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConsumeResponse {
+    consumed: bool,
+}
+
+/// Check-and-mark an invite consumed. Returns true only if this call consumed it
+/// (present, unused, and unexpired) - false if it was already used, expired, or never
+/// existed. Atomic against concurrent redemptions of the same token when the
+/// `INVITE_GATE` Durable Object binding is configured; racy (best-effort) otherwise.
+pub async fn consume_invite(env: &Env, payload: &InvitePayload) -> Result<bool> {
+    let now = Date::now().as_millis() / 1000;
+    if now >= payload.exp {
+        return Ok(false);
+    }
+
+    match env.durable_object("INVITE_GATE") {
+        Ok(namespace) => consume_invite_durable(&namespace, &payload.token_id).await,
+        Err(_) => consume_invite_kv(env, &payload.token_id).await,
+    }
+}
+
+/// Atomic path: one Durable Object instance per invite token id, so the get-then-mark
+/// inside its single `fetch` handler can't race the way separate KV get/put calls can.
+async fn consume_invite_durable(namespace: &ObjectNamespace, token_id: &str) -> Result<bool> {
+    let id = namespace.id_from_name(token_id)?;
+    let stub = id.get_stub()?;
+
+    let req_init = RequestInit {
+        method: Method::Post,
+        ..Default::default()
+    };
+    let request = Request::new_with_init("https://invite-gate/consume", &req_init)?;
+
+    let mut resp = stub.fetch_with_request(request).await?;
+    let result: ConsumeResponse = resp.json().await?;
+    Ok(result.consumed)
+}
+
+/// Legacy fallback path, kept so local `wrangler dev` without Durable Objects
+/// configured still gates redemption. This is a get-then-put race: two concurrent
+/// `consume_invite_kv` calls for the same token can both observe `used: false` and
+/// both return `true`, admitting a double redeem. Acceptable only because it's
+/// local-dev-only - the Durable Object path above is what production relies on.
+async fn consume_invite_kv(env: &Env, token_id: &str) -> Result<bool> {
+    let invites = env.kv("INVITES")?;
+    let key = storage_key(token_id);
+
+    match invites.get(&key).json::<InviteRecord>().await? {
+        Some(record) if !record.used => {
+            invites
+                .put(&key, serde_json::to_string(&InviteRecord { used: true })?)?
+                .execute()
+                .await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Durable Object backing the atomic invite-consumption flag. One instance exists per
+/// invite token id (see `id_from_name` above), so its transactional storage serializes
+/// the check-and-mark without a separate get/put race.
+#[durable_object]
+pub struct InviteGate {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for InviteGate {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, _req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let used: bool = storage.get("used").await.unwrap_or(false);
+
+        if used {
+            return Response::from_json(&ConsumeResponse { consumed: false });
+        }
+
+        storage.put("used", &true).await?;
+        Response::from_json(&ConsumeResponse { consumed: true })
+    }
+}
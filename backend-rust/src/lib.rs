@@ -1,6 +1,9 @@
+mod group;
+mod invite;
 mod opaque;
 mod rate_limit;
 mod routes;
+mod session;
 
 use serde::Serialize;
 use std::collections::HashMap;
@@ -37,22 +40,61 @@ async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
 
     console_log!("[opaque] {} {}", req.method(), path);
 
-    // Load OPAQUE server setup from worker secret
-    // Note: Using traditional secret due to workers-rs SecretStore bug
+    // Load OPAQUE server setups from worker secrets
+    // Note: Using traditional secrets due to workers-rs SecretStore bug
     // https://github.com/cloudflare/workers-rs/issues/919
-    let setup_secret = env.secret("OPAQUE_SERVER_SETUP")?.to_string();
-
-    let server_setup = opaque::init_server_setup(Some(&setup_secret)).map_err(Error::from)?;
+    let server_setups = load_server_setups(&env).map_err(Error::from)?;
+    let invite_key = env.secret("INVITE_KEY")?.to_string();
 
     match (req.method(), path.as_str()) {
-        (Method::Post, "/auth/opaque/register/start") => routes::handle_register_start(req, &env, &server_setup).await,
-        (Method::Post, "/auth/opaque/register/finish") => routes::handle_register_finish(req, &env).await,
-        (Method::Post, "/auth/opaque/login/start") => routes::handle_login_start(req, &env, &server_setup).await,
+        (Method::Post, "/auth/opaque/register/start") => {
+            routes::handle_register_start(req, &env, server_setups.current()).await
+        }
+        (Method::Post, "/auth/opaque/register/finish") => {
+            routes::handle_register_finish(
+                req,
+                &env,
+                server_setups.current_version(),
+                invite_key.as_bytes(),
+            )
+            .await
+        }
+        (Method::Post, "/auth/opaque/login/start") => routes::handle_login_start(req, &env, &server_setups).await,
         (Method::Post, "/auth/opaque/login/finish") => routes::handle_login_finish(req, &env).await,
+        (Method::Post, "/auth/opaque/rekey") => {
+            routes::handle_rekey(req, &env, server_setups.current_version()).await
+        }
+        (Method::Post, "/auth/logout") => routes::handle_logout(req, &env).await,
+        (Method::Get, "/bundle") => routes::handle_get_bundle(req, &env).await,
+        (Method::Put, "/bundle") => routes::handle_put_bundle(req, &env).await,
+        (Method::Delete, "/bundle") => routes::handle_delete_bundle(req, &env).await,
+        (Method::Post, "/auth/invite") => routes::handle_create_invite(req, &env, invite_key.as_bytes()).await,
+        (Method::Get, "/group/members") => routes::handle_group_members(req, &env).await,
         _ => Response::error("Not found", 404),
     }
 }
 
+/// Load the current OPAQUE server setup plus any retired setups kept for key
+/// rotation, in secret-read order (current first, then `OPAQUE_SERVER_SETUP_PREV_1`,
+/// `_2`, ... tried in order until one is missing). `ServerSetups::new` reorders these
+/// into stable version-tagged slots - see its doc comment for why the *read* order
+/// isn't the *version* order.
+fn load_server_setups(env: &Env) -> std::result::Result<opaque::ServerSetups, String> {
+    let current_b64 = env
+        .secret("OPAQUE_SERVER_SETUP")
+        .map_err(|e| format!("Missing OPAQUE_SERVER_SETUP: {}", e))?
+        .to_string();
+    let mut setups = vec![opaque::init_server_setup(Some(&current_b64))?];
+
+    let mut i = 1u8;
+    while let Ok(secret) = env.secret(&format!("OPAQUE_SERVER_SETUP_PREV_{}", i)) {
+        setups.push(opaque::init_server_setup(Some(&secret.to_string()))?);
+        i += 1;
+    }
+
+    Ok(opaque::ServerSetups::new(setups))
+}
+
 async fn handle_ready(env: &Env) -> Result<Response> {
     let mut checks = HashMap::new();
     let mut all_ok = true;
@@ -74,6 +116,9 @@ async fn handle_ready(env: &Env) -> Result<Response> {
         ("kv_bundles", "BUNDLES"),
         ("kv_login_states", "LOGIN_STATES"),
         ("kv_rate_limits", "RATE_LIMITS"),
+        ("kv_sessions", "SESSIONS"),
+        ("kv_invites", "INVITES"),
+        ("kv_groups", "GROUPS"),
     ] {
         match env.kv(kv_name) {
             Ok(kv) => match kv.get("__healthcheck__").text().await {
@@ -101,8 +146,8 @@ async fn handle_ready(env: &Env) -> Result<Response> {
 fn cors_preflight() -> Result<Response> {
     let headers = Headers::new();
     headers.set("Access-Control-Allow-Origin", "*")?;
-    headers.set("Access-Control-Allow-Methods", "GET, POST, OPTIONS")?;
-    headers.set("Access-Control-Allow-Headers", "Content-Type")?;
+    headers.set("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS")?;
+    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization, If-Match")?;
     headers.set("Access-Control-Max-Age", "86400")?;
     Ok(Response::empty()?.with_headers(headers))
 }
@@ -8,6 +8,9 @@ use opaque_ke::{
 use argon2::Argon2;
 use sha2::Sha512;
 use rand::rngs::OsRng;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use hkdf::Hkdf;
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 
 /// Cipher suite matching iOS OpaqueSwift configuration
@@ -45,6 +48,53 @@ pub fn serialize_server_setup(setup: &OpaqueServerSetup) -> String {
     BASE64.encode(setup.serialize())
 }
 
+/// Ordered set of server setups, supporting key rotation without breaking existing
+/// registrations. A setup's index is its `v` (setup version), tagged onto each stored
+/// credential at registration time so login can find the matching setup later. The
+/// *current* setup - used for new registrations and rekeys - always holds the highest
+/// index; retired setups are kept at their original, never-changing index only so
+/// already-registered credentials can still complete login until the client re-keys
+/// via `/auth/opaque/rekey`.
+///
+/// Version numbers must never shift: if adding a new current setup renumbered the
+/// existing entries, every credential tagged with an old version would suddenly
+/// resolve to the wrong setup and fail to log in. `new` takes setups in the order
+/// they're read from secrets - current first, then `OPAQUE_SERVER_SETUP_PREV_1`, `_2`,
+/// ... - and reorders them internally so each one keeps the index it was first
+/// assigned: `PREV_1` is always version 0, `PREV_2` is always version 1, and so on,
+/// with the current setup's version growing by one on every rotation.
+pub struct ServerSetups {
+    setups: Vec<OpaqueServerSetup>,
+}
+
+impl ServerSetups {
+    /// `loaded` must be in secret-read order: current setup first, then
+    /// `OPAQUE_SERVER_SETUP_PREV_1`, `_2`, ... See the type doc comment for why this
+    /// is reordered rather than indexed as given.
+    pub fn new(mut loaded: Vec<OpaqueServerSetup>) -> Self {
+        if !loaded.is_empty() {
+            let current = loaded.remove(0);
+            loaded.push(current);
+        }
+        Self { setups: loaded }
+    }
+
+    /// The setup new registrations and rekeys are issued under.
+    pub fn current(&self) -> &OpaqueServerSetup {
+        self.setups.last().expect("ServerSetups must hold at least one setup")
+    }
+
+    /// The version tag new registrations and rekeys should record.
+    pub fn current_version(&self) -> u8 {
+        (self.setups.len() - 1) as u8
+    }
+
+    /// Look up the setup a credential was registered under by its stored version tag.
+    pub fn get(&self, version: u8) -> Option<&OpaqueServerSetup> {
+        self.setups.get(version as usize)
+    }
+}
+
 #[derive(Debug)]
 pub struct RegistrationStartResult {
     pub response: Vec<u8>,
@@ -76,32 +126,58 @@ pub struct LoginStartResult {
     pub state: Vec<u8>,
 }
 
-/// Start login - process client's credential request
+/// Start login - process client's credential request.
+///
+/// `password_file` is `None` for unknown users; `ServerLogin::start` then fabricates a
+/// fake credential response so the reply shape doesn't reveal whether the account
+/// exists (RFC 9807 §10.9). Callers select which `server_setup` to pass in based on the
+/// stored credential's setup version (see `ServerSetups`).
 pub fn start_login(
     server_setup: &OpaqueServerSetup,
     client_identifier: &[u8],
-    password_file: &[u8],
+    password_file: Option<&[u8]>,
     credential_request: &[u8],
 ) -> Result<LoginStartResult, String> {
     let request = CredentialRequest::<DefaultCipherSuite>::deserialize(credential_request)
         .map_err(|_| "Failed to deserialize credential request")?;
 
     // Password file is stored as RegistrationUpload, but ServerLogin::start expects ServerRegistration
-    let password = RegistrationUpload::<DefaultCipherSuite>::deserialize(password_file)
-        .map_err(|_| "Failed to deserialize password file")?;
-
-    // Complete the registration to get ServerRegistration
-    let server_registration = ServerRegistration::finish(password);
-
-    let mut rng = OsRng;
-    let result = ServerLogin::start(
-        &mut rng,
-        server_setup,
-        Some(server_registration),
-        request,
-        client_identifier,
-        ServerLoginParameters::default(),
-    ).map_err(|_| "Failed to start login")?;
+    let server_registration = match password_file {
+        Some(bytes) => {
+            let password = RegistrationUpload::<DefaultCipherSuite>::deserialize(bytes)
+                .map_err(|_| "Failed to deserialize password file")?;
+            Some(ServerRegistration::finish(password))
+        }
+        None => None,
+    };
+
+    // Unknown users (server_registration = None) get a fabricated fake credential so the
+    // response doesn't reveal non-registration (RFC 9807 §10.9). That fake response must
+    // also be deterministic per client_identifier - otherwise replaying the same
+    // start_login_request twice for a nonexistent user yields two differently-shaped
+    // evaluations, which itself leaks non-registration. Real users keep using OsRng.
+    let result = if server_registration.is_some() {
+        let mut rng = OsRng;
+        ServerLogin::start(
+            &mut rng,
+            server_setup,
+            server_registration,
+            request,
+            client_identifier,
+            ServerLoginParameters::default(),
+        )
+    } else {
+        let mut rng = fake_record_rng(server_setup, client_identifier);
+        ServerLogin::start(
+            &mut rng,
+            server_setup,
+            server_registration,
+            request,
+            client_identifier,
+            ServerLoginParameters::default(),
+        )
+    }
+    .map_err(|_| "Failed to start login")?;
 
     Ok(LoginStartResult {
         response: result.message.serialize().to_vec(),
@@ -109,6 +185,18 @@ pub fn start_login(
     })
 }
 
+/// Derive a deterministic RNG for fabricating the fake credential response given to
+/// unknown-user login attempts. Seeded from `HKDF-SHA512(salt = "opaque-fake-record",
+/// ikm = server_setup, info = client_identifier)`, so the same client_identifier always
+/// materializes byte-identical fake output regardless of how many times it's probed.
+fn fake_record_rng(server_setup: &OpaqueServerSetup, client_identifier: &[u8]) -> ChaCha20Rng {
+    let hk = Hkdf::<Sha512>::new(Some(b"opaque-fake-record"), server_setup.serialize().as_slice());
+    let mut seed = [0u8; 32];
+    hk.expand(client_identifier, &mut seed)
+        .expect("32 bytes is a valid HKDF-SHA512 output length");
+    ChaCha20Rng::from_seed(seed)
+}
+
 #[derive(Debug)]
 pub struct LoginFinishResult {
     pub session_key: Vec<u8>,
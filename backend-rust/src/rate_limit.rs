@@ -1,6 +1,11 @@
 //! Rate limiting for OPAQUE authentication endpoints
 //!
-//! Uses Cloudflare KV with sliding window algorithm.
+//! Primary path: a Cloudflare Durable Object (one instance per client
+//! identifier) holds the sliding-window counter in its transactional
+//! storage and increments it inside a single `fetch` handler, so the
+//! check-then-increment is atomic per client. Falls back to the old
+//! Cloudflare KV sliding window when the `RATE_LIMITER` Durable Object
+//! binding isn't configured (e.g. local `wrangler dev` without DOs set up).
 //! Keys: `rate:{client_identifier}:{endpoint}` with TTL-based expiry.
 
 use serde::{Deserialize, Serialize};
@@ -23,20 +28,107 @@ impl Default for RateLimitConfig {
     }
 }
 
-/// Rate limit entry stored in KV
-#[derive(Serialize, Deserialize)]
+/// Rate limit entry stored in KV / Durable Object storage
+#[derive(Serialize, Deserialize, Default)]
 struct RateLimitEntry {
     count: u32,
     window_start: u64,
 }
 
-/// Check and update rate limit for a client identifier + endpoint combination.
-/// Returns Ok(()) if request is allowed, Err with remaining seconds if rate limited.
+#[derive(Serialize, Deserialize)]
+struct RateLimitCheckRequest {
+    max_requests: u32,
+    window_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RateLimitCheckResponse {
+    allowed: bool,
+    retry_after: u64,
+}
+
+/// Check and update the rate limit for a client identifier + endpoint combination.
+/// Returns Ok(()) if the request is allowed, Err with remaining seconds if rate limited.
+///
+/// Prefers the `RATE_LIMITER` Durable Object binding for atomic windows; falls back to
+/// the KV-backed sliding window (best-effort, racy under concurrent requests) when the
+/// binding is absent.
+pub async fn check_rate_limit(
+    env: &Env,
+    client_identifier: &str,
+    endpoint: &str,
+    config: &RateLimitConfig,
+) -> std::result::Result<(), u64> {
+    match env.durable_object("RATE_LIMITER") {
+        Ok(namespace) => check_rate_limit_durable(&namespace, client_identifier, endpoint, config).await,
+        Err(_) => {
+            let kv = env.kv("RATE_LIMITS").map_err(|_| config.window_seconds)?;
+            check_rate_limit_kv(&kv, client_identifier, endpoint, config).await
+        }
+    }
+}
+
+/// Atomic path: one Durable Object instance per client identifier, keyed further by
+/// endpoint inside its storage so a single client's windows across endpoints don't
+/// collide.
+async fn check_rate_limit_durable(
+    namespace: &ObjectNamespace,
+    client_identifier: &str,
+    endpoint: &str,
+    config: &RateLimitConfig,
+) -> std::result::Result<(), u64> {
+    let id = match namespace.id_from_name(client_identifier) {
+        Ok(id) => id,
+        Err(_) => return Ok(()), // fail-open: DO misconfiguration shouldn't block auth
+    };
+    let stub = match id.get_stub() {
+        Ok(stub) => stub,
+        Err(_) => return Ok(()),
+    };
+
+    let check = RateLimitCheckRequest {
+        max_requests: config.max_requests,
+        window_seconds: config.window_seconds,
+    };
+    let body = match serde_json::to_string(&check) {
+        Ok(b) => b,
+        Err(_) => return Ok(()),
+    };
+
+    let req_init = RequestInit {
+        method: Method::Post,
+        body: Some(body.into()),
+        ..Default::default()
+    };
+    let request = match Request::new_with_init(&format!("https://rate-limiter/{}", endpoint), &req_init) {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+
+    let mut resp = match stub.fetch_with_request(request).await {
+        Ok(r) => r,
+        Err(_) => return Ok(()), // fail-open: DO unreachable, primary limiting is at Cloudflare edge
+    };
+
+    let result: RateLimitCheckResponse = match resp.json().await {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+
+    if result.allowed {
+        Ok(())
+    } else {
+        Err(result.retry_after)
+    }
+}
+
+/// Legacy fallback path, kept so local `wrangler dev` without Durable Objects configured
+/// still enforces a best-effort limit.
 ///
 /// Note: The read-then-write pattern has a race condition, but this is acceptable because:
 /// 1. This is defense-in-depth (Cloudflare edge rate limiting is the primary mechanism)
 /// 2. Cloudflare KV has eventual consistency anyway
-pub async fn check_rate_limit(
+async fn check_rate_limit_kv(
     kv: &kv::KvStore,
     client_identifier: &str,
     endpoint: &str,
@@ -83,8 +175,56 @@ pub async fn check_rate_limit(
     }
 }
 
+/// Durable Object backing the atomic rate-limit counter. One instance exists per
+/// client identifier (see `id_from_name` above), so its transactional storage
+/// serializes the increment without a separate get/put race.
+#[durable_object]
+pub struct RateLimiter {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for RateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let endpoint = req.path();
+        let check: RateLimitCheckRequest = req.json().await?;
+        let storage = self.state.storage();
+        let storage_key = format!("entry:{}", endpoint);
+
+        let now = Date::now().as_millis() / 1000;
+        let mut entry: RateLimitEntry = storage.get(&storage_key).await.unwrap_or_default();
+
+        if now < entry.window_start + check.window_seconds {
+            if entry.count >= check.max_requests {
+                let retry_after = (entry.window_start + check.window_seconds) - now;
+                return Response::from_json(&RateLimitCheckResponse {
+                    allowed: false,
+                    retry_after,
+                });
+            }
+            entry.count += 1;
+        } else {
+            entry = RateLimitEntry {
+                count: 1,
+                window_start: now,
+            };
+        }
+
+        storage.put(&storage_key, &entry).await?;
+
+        Response::from_json(&RateLimitCheckResponse {
+            allowed: true,
+            retry_after: 0,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    // Note: KV tests require wrangler dev or miniflare
+    // Note: KV/Durable Object tests require wrangler dev or miniflare
     // Unit tests focus on serialization logic
 }
@@ -1,4 +1,8 @@
+use crate::group;
+use crate::invite;
 use crate::opaque;
+use crate::rate_limit::{self, RateLimitConfig};
+use crate::session;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
 use worker::*;
@@ -23,6 +27,9 @@ pub struct RegisterFinishRequest {
     pub client_identifier: String,
     pub registration_record: String, // base64
     pub encrypted_bundle: Option<String>,
+    /// Invite token minted by `POST /auth/invite`. Required when registration is
+    /// gated to existing group members rather than open.
+    pub invite: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -57,6 +64,7 @@ pub struct LoginFinishRequest {
 pub struct LoginFinishResponse {
     pub success: bool,
     pub session_key: String,
+    pub session_token: String,
     pub encrypted_bundle: Option<String>,
 }
 
@@ -65,9 +73,107 @@ pub struct ErrorResponse {
     pub error: String,
 }
 
+/// Encrypted bundle as stored in the `BUNDLES` KV. `version` backs the optimistic-
+/// concurrency check on `PUT /bundle`.
+#[derive(Serialize, Deserialize, Clone)]
+struct BundleRecord {
+    version: u64,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutBundleRequest {
+    pub encrypted_bundle: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleResponse {
+    pub encrypted_bundle: String,
+    pub version: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionConflictResponse {
+    pub error: String,
+    pub current_version: u64,
+}
+
+fn bundle_key(client_identifier: &str) -> String {
+    format!("bundle:{}", client_identifier)
+}
+
+/// Parse a `BUNDLES` KV value, accepting both the current JSON encoding and the
+/// pre-versioning format (a bare base64 ciphertext with no wrapping object). Bundles
+/// written before optimistic-concurrency versioning shipped are treated as version 1,
+/// matching the version `handle_register_finish` stores a freshly-uploaded bundle at.
+fn parse_stored_bundle(text: String) -> BundleRecord {
+    serde_json::from_str(&text).unwrap_or(BundleRecord {
+        version: 1,
+        ciphertext: text,
+    })
+}
+
+/// Read and parse a client's stored bundle, if any, tolerating the pre-versioning
+/// bare-ciphertext format.
+async fn get_stored_bundle(bundles: &kv::KvStore, key: &str) -> Result<Option<BundleRecord>> {
+    match bundles.get(key).text().await? {
+        Some(text) => Ok(Some(parse_stored_bundle(text))),
+        None => Ok(None),
+    }
+}
+
+/// Registration record as stored in the `CREDENTIALS` KV. `v` is the `ServerSetups`
+/// index it was registered under, so `handle_login_start` can select the matching
+/// setup even after the server's key has been rotated.
+#[derive(Serialize, Deserialize)]
+struct StoredCredential {
+    v: u8,
+    record: String,
+}
+
+fn credential_key(client_identifier: &str) -> String {
+    format!("cred:{}", client_identifier)
+}
+
+/// Parse a `CREDENTIALS` KV value, accepting both the current JSON encoding and the
+/// pre-versioning format (a bare base64 registration record with no wrapping object).
+/// Credentials written before setup versioning shipped are treated as `v: 0`, which is
+/// the version `ServerSetups::current_version` starts numbering from.
+fn parse_stored_credential(text: String) -> StoredCredential {
+    serde_json::from_str(&text).unwrap_or(StoredCredential { v: 0, record: text })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RekeyRequest {
+    pub registration_record: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteRequest {
+    pub group_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteResponse {
+    pub invite_token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMembersResponse {
+    pub group_id: String,
+    pub members: Vec<String>,
+}
+
 pub async fn handle_register_start(
     mut req: Request,
-    _env: &Env,
+    env: &Env,
     server_setup: &opaque::OpaqueServerSetup,
 ) -> Result<Response> {
     let body: RegisterStartRequest = match parse_json(&mut req).await {
@@ -79,6 +185,12 @@ pub async fn handle_register_start(
         &body.client_identifier[..8.min(body.client_identifier.len())]
     );
 
+    if let Err(retry_after) =
+        rate_limit::check_rate_limit(env, &body.client_identifier, "register", &RateLimitConfig::default()).await
+    {
+        return rate_limited_response(retry_after);
+    }
+
     // Validate client identifier (64 hex chars = 32 bytes SHA256)
     if body.client_identifier.len() != 64 {
         return json_response(
@@ -109,7 +221,12 @@ pub async fn handle_register_start(
     )
 }
 
-pub async fn handle_register_finish(mut req: Request, env: &Env) -> Result<Response> {
+pub async fn handle_register_finish(
+    mut req: Request,
+    env: &Env,
+    setup_version: u8,
+    invite_key: &[u8],
+) -> Result<Response> {
     let body: RegisterFinishRequest = match parse_json(&mut req).await {
         Ok(b) => b,
         Err(r) => return r,
@@ -124,8 +241,26 @@ pub async fn handle_register_finish(mut req: Request, env: &Env) -> Result<Respo
         );
     }
 
+    // If onboarding is gated to existing group members, check the invite's signature
+    // up front, but don't consume it yet - a duplicate registration or a malformed
+    // record below must not burn a single-use token the invitee still needs.
+    let invite_payload = match &body.invite {
+        Some(token) => match invite::verify_token(invite_key, token) {
+            Some(payload) => Some(payload),
+            None => {
+                return json_response(
+                    &ErrorResponse {
+                        error: "Invalid invite token".into(),
+                    },
+                    400,
+                )
+            }
+        },
+        None => None,
+    };
+
     let credentials = env.kv("CREDENTIALS")?;
-    let key = format!("cred:{}", body.client_identifier);
+    let key = credential_key(&body.client_identifier);
 
     // Check if user already exists
     if credentials.get(&key).text().await?.is_some() {
@@ -147,14 +282,40 @@ pub async fn handle_register_finish(mut req: Request, env: &Env) -> Result<Respo
         );
     }
 
-    // Store the registration record as the password file
-    credentials.put(&key, &body.registration_record)?.execute().await?;
+    // Only now, immediately before we commit the credential, consume the invite - this
+    // is the last point before the registration is guaranteed to succeed.
+    if let Some(payload) = &invite_payload {
+        if !invite::consume_invite(env, payload).await? {
+            return json_response(
+                &ErrorResponse {
+                    error: "Invite already used or expired".into(),
+                },
+                400,
+            );
+        }
+    }
+
+    // Store the registration record as the password file, tagged with the setup
+    // version it was created under so login can select the matching setup later.
+    let stored = StoredCredential {
+        v: setup_version,
+        record: body.registration_record.clone(),
+    };
+    credentials.put(&key, serde_json::to_string(&stored)?)?.execute().await?;
+
+    if let Some(payload) = invite_payload {
+        group::add_member(env, &payload.group_id, &body.client_identifier).await?;
+    }
 
     // Store initial bundle if provided
     if let Some(bundle) = body.encrypted_bundle {
         let bundles = env.kv("BUNDLES")?;
+        let record = BundleRecord {
+            version: 1,
+            ciphertext: bundle,
+        };
         bundles
-            .put(&format!("bundle:{}", body.client_identifier), &bundle)?
+            .put(&bundle_key(&body.client_identifier), serde_json::to_string(&record)?)?
             .execute()
             .await?;
     }
@@ -170,7 +331,7 @@ pub async fn handle_register_finish(mut req: Request, env: &Env) -> Result<Respo
 pub async fn handle_login_start(
     mut req: Request,
     env: &Env,
-    server_setup: &opaque::OpaqueServerSetup,
+    server_setups: &opaque::ServerSetups,
 ) -> Result<Response> {
     let body: LoginStartRequest = match parse_json(&mut req).await {
         Ok(b) => b,
@@ -181,6 +342,12 @@ pub async fn handle_login_start(
         &body.client_identifier[..8.min(body.client_identifier.len())]
     );
 
+    if let Err(retry_after) =
+        rate_limit::check_rate_limit(env, &body.client_identifier, "login_start", &RateLimitConfig::default()).await
+    {
+        return rate_limited_response(retry_after);
+    }
+
     if body.client_identifier.len() != 64 {
         return json_response(
             &ErrorResponse {
@@ -192,36 +359,40 @@ pub async fn handle_login_start(
 
     // Get password file (None for unknown users triggers fake record per RFC 9807 §10.9)
     let credentials = env.kv("CREDENTIALS")?;
-    let key = format!("cred:{}", body.client_identifier);
-
-    let password_file_b64 = credentials.get(&key).text().await?;
-    let is_fake_record = password_file_b64.is_none();
+    let key = credential_key(&body.client_identifier);
 
-    let password_file: Option<Vec<u8>> = match password_file_b64 {
-        Some(pf_b64) => Some(
-            BASE64
-                .decode(&pf_b64)
-                .map_err(|_| Error::from("Corrupted password file"))?,
-        ),
+    let stored = match credentials.get(&key).text().await? {
+        Some(text) => Some(parse_stored_credential(text)),
+        None => None,
+    };
+    let is_fake_record = stored.is_none();
+
+    let (password_file, setup): (Option<Vec<u8>>, &opaque::OpaqueServerSetup) = match &stored {
+        Some(cred) => {
+            let bytes = BASE64
+                .decode(&cred.record)
+                .map_err(|_| Error::from("Corrupted password file"))?;
+            let setup = server_setups
+                .get(cred.v)
+                .ok_or_else(|| Error::from("Unknown server setup version for credential"))?;
+            console_log!("[opaque/login/start] Found password file, {} bytes", bytes.len());
+            (Some(bytes), setup)
+        }
         None => {
             console_log!(
                 "[opaque/login/start] Unknown user, using fake record: {}...",
                 &body.client_identifier[..8]
             );
-            None
+            (None, server_setups.current())
         }
     };
 
-    if let Some(ref pf) = password_file {
-        console_log!("[opaque/login/start] Found password file, {} bytes", pf.len());
-    }
-
     let request_bytes = BASE64
         .decode(&body.start_login_request)
         .map_err(|_| Error::from("Invalid base64 in startLoginRequest"))?;
 
     let result = opaque::start_login(
-        server_setup,
+        setup,
         body.client_identifier.as_bytes(),
         password_file.as_deref(),
         &request_bytes,
@@ -264,6 +435,12 @@ pub async fn handle_login_finish(mut req: Request, env: &Env) -> Result<Response
         Err(r) => return r,
     };
 
+    if let Err(retry_after) =
+        rate_limit::check_rate_limit(env, &body.client_identifier, "login_finish", &RateLimitConfig::default()).await
+    {
+        return rate_limited_response(retry_after);
+    }
+
     if body.client_identifier.len() != 64 {
         return json_response(
             &ErrorResponse {
@@ -335,10 +512,11 @@ pub async fn handle_login_finish(mut req: Request, env: &Env) -> Result<Response
 
     // Get user's encrypted bundle
     let bundles = env.kv("BUNDLES")?;
-    let encrypted_bundle = bundles
-        .get(&format!("bundle:{}", body.client_identifier))
-        .text()
-        .await?;
+    let encrypted_bundle = get_stored_bundle(&bundles, &bundle_key(&body.client_identifier))
+        .await?
+        .map(|record| record.ciphertext);
+
+    let session_token = session::create_session(env, &body.client_identifier).await?;
 
     console_log!(
         "[opaque/login/finish] Successful login: {}...",
@@ -349,12 +527,288 @@ pub async fn handle_login_finish(mut req: Request, env: &Env) -> Result<Response
         &LoginFinishResponse {
             success: true,
             session_key: BASE64.encode(&result.session_key),
+            session_token,
             encrypted_bundle,
         },
         200,
     )
 }
 
+/// Let a logged-in client re-upload a fresh registration record under the current
+/// setup. The client runs a normal OPAQUE registration (via `register/start`) against
+/// the current setup, then posts the resulting `registration_record` here instead of
+/// to `register/finish` so it overwrites the existing credential rather than being
+/// rejected as a duplicate. Used to retire credentials tagged with an old setup
+/// version after a key rotation.
+pub async fn handle_rekey(mut req: Request, env: &Env, current_setup_version: u8) -> Result<Response> {
+    let client_identifier = match authenticate(&req, env).await {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+
+    let body: RekeyRequest = match parse_json(&mut req).await {
+        Ok(b) => b,
+        Err(r) => return r,
+    };
+
+    if BASE64.decode(&body.registration_record).is_err() {
+        return json_response(
+            &ErrorResponse {
+                error: "Invalid registration record format".into(),
+            },
+            400,
+        );
+    }
+
+    let credentials = env.kv("CREDENTIALS")?;
+    let stored = StoredCredential {
+        v: current_setup_version,
+        record: body.registration_record,
+    };
+    credentials
+        .put(&credential_key(&client_identifier), serde_json::to_string(&stored)?)?
+        .execute()
+        .await?;
+
+    console_log!(
+        "[opaque/rekey] Re-keyed credential: {}...",
+        &client_identifier[..8.min(client_identifier.len())]
+    );
+
+    json_response(&SuccessResponse { success: true }, 200)
+}
+
+/// Mint a single-use invite token so an existing group member can bring in a relative.
+/// Only callable by a member of `group_id` itself - otherwise any authenticated user
+/// could mint invites into (and so onboard outsiders into) another family's group.
+pub async fn handle_create_invite(mut req: Request, env: &Env, invite_key: &[u8]) -> Result<Response> {
+    let client_identifier = match authenticate(&req, env).await {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+
+    let body: InviteRequest = match parse_json(&mut req).await {
+        Ok(b) => b,
+        Err(r) => return r,
+    };
+
+    match group::member_group(env, &client_identifier).await? {
+        Some(group_id) if group_id == body.group_id => {}
+        _ => {
+            return json_response(
+                &ErrorResponse {
+                    error: "Not a member of this group".into(),
+                },
+                403,
+            )
+        }
+    }
+
+    let invite_token = invite::create_invite(env, invite_key, &body.group_id, &client_identifier).await?;
+
+    json_response(&InviteResponse { invite_token }, 200)
+}
+
+/// List the other members of the authenticated caller's family group.
+pub async fn handle_group_members(req: Request, env: &Env) -> Result<Response> {
+    let client_identifier = match authenticate(&req, env).await {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+
+    let group_id = match group::member_group(env, &client_identifier).await? {
+        Some(id) => id,
+        None => {
+            return json_response(
+                &ErrorResponse {
+                    error: "Not a member of any group".into(),
+                },
+                404,
+            )
+        }
+    };
+
+    let members = group::list_members(env, &group_id).await?;
+
+    json_response(&GroupMembersResponse { group_id, members }, 200)
+}
+
+/// Resolve the caller's `client_identifier` from a `Bearer` session token, 401ing on a
+/// missing header or an unknown/expired session.
+pub async fn authenticate(req: &Request, env: &Env) -> std::result::Result<String, Result<Response>> {
+    let token = match bearer_token(req) {
+        Some(t) => t,
+        None => {
+            return Err(json_response(
+                &ErrorResponse {
+                    error: "Missing or invalid Authorization header".into(),
+                },
+                401,
+            ))
+        }
+    };
+
+    match session::lookup_session(env, &token).await {
+        Ok(Some(client_identifier)) => Ok(client_identifier),
+        Ok(None) => Err(json_response(
+            &ErrorResponse {
+                error: "Invalid or expired session".into(),
+            },
+            401,
+        )),
+        Err(e) => Err(Err(e)),
+    }
+}
+
+pub async fn handle_logout(req: Request, env: &Env) -> Result<Response> {
+    let token = match bearer_token(&req) {
+        Some(t) => t,
+        None => {
+            return json_response(
+                &ErrorResponse {
+                    error: "Missing or invalid Authorization header".into(),
+                },
+                401,
+            )
+        }
+    };
+
+    session::delete_session(env, &token).await?;
+
+    json_response(&SuccessResponse { success: true }, 200)
+}
+
+pub async fn handle_get_bundle(req: Request, env: &Env) -> Result<Response> {
+    let client_identifier = match authenticate(&req, env).await {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+
+    let bundles = env.kv("BUNDLES")?;
+    let record = match get_stored_bundle(&bundles, &bundle_key(&client_identifier)).await? {
+        Some(r) => r,
+        None => {
+            return json_response(
+                &ErrorResponse {
+                    error: "No bundle found".into(),
+                },
+                404,
+            )
+        }
+    };
+
+    bundle_response(&record, 200)
+}
+
+pub async fn handle_put_bundle(mut req: Request, env: &Env) -> Result<Response> {
+    let client_identifier = match authenticate(&req, env).await {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+
+    let expected_version: u64 = match req
+        .headers()
+        .get("If-Match")
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse().ok())
+    {
+        Some(v) => v,
+        None => {
+            return json_response(
+                &ErrorResponse {
+                    error: "Missing or invalid If-Match header".into(),
+                },
+                400,
+            )
+        }
+    };
+
+    let body: PutBundleRequest = match parse_json(&mut req).await {
+        Ok(b) => b,
+        Err(r) => return r,
+    };
+
+    let bundles = env.kv("BUNDLES")?;
+    let key = bundle_key(&client_identifier);
+    let current = get_stored_bundle(&bundles, &key).await?;
+    let current_version = current.map(|r| r.version).unwrap_or(0);
+
+    if current_version != expected_version {
+        return version_conflict_response(current_version);
+    }
+
+    let record = BundleRecord {
+        version: current_version + 1,
+        ciphertext: body.encrypted_bundle,
+    };
+    bundles.put(&key, serde_json::to_string(&record)?)?.execute().await?;
+
+    bundle_response(&record, 200)
+}
+
+pub async fn handle_delete_bundle(req: Request, env: &Env) -> Result<Response> {
+    let client_identifier = match authenticate(&req, env).await {
+        Ok(id) => id,
+        Err(r) => return r,
+    };
+
+    let bundles = env.kv("BUNDLES")?;
+    bundles.delete(&bundle_key(&client_identifier)).await?;
+
+    json_response(&SuccessResponse { success: true }, 200)
+}
+
+/// Build a 200/201-style bundle response with the version surfaced in both the JSON
+/// body and the `ETag` header, so a client can cache the header for the next `If-Match`.
+fn bundle_response(record: &BundleRecord, status: u16) -> Result<Response> {
+    let body = serde_json::to_string(&BundleResponse {
+        encrypted_bundle: record.ciphertext.clone(),
+        version: record.version,
+    })?;
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set("ETag", &record.version.to_string())?;
+
+    Response::from_body(ResponseBody::Body(body.into_bytes())).map(|r| r.with_status(status).with_headers(headers))
+}
+
+/// Build a 409 response carrying the current version in a structured field and the
+/// `ETag` header (mirroring `bundle_response`), so a client can merge and retry
+/// without having to scrape the version out of the error message.
+fn version_conflict_response(current_version: u64) -> Result<Response> {
+    let body = serde_json::to_string(&VersionConflictResponse {
+        error: format!("Version conflict, current version is {}", current_version),
+        current_version,
+    })?;
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set("ETag", &current_version.to_string())?;
+
+    Response::from_body(ResponseBody::Body(body.into_bytes())).map(|r| r.with_status(409).with_headers(headers))
+}
+
+fn bearer_token(req: &Request) -> Option<String> {
+    let header = req.headers().get("Authorization").ok().flatten()?;
+    header.strip_prefix("Bearer ").map(|s| s.trim().to_string())
+}
+
+/// Build a 429 response with a `Retry-After` header set from the rate limiter's
+/// remaining-seconds value.
+fn rate_limited_response(retry_after: u64) -> Result<Response> {
+    let body = serde_json::to_string(&ErrorResponse {
+        error: "Too many requests".into(),
+    })?;
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set("Retry-After", &retry_after.to_string())?;
+
+    Response::from_body(ResponseBody::Body(body.into_bytes())).map(|r| r.with_status(429).with_headers(headers))
+}
+
 fn json_response<T: Serialize>(data: &T, status: u16) -> Result<Response> {
     let body = serde_json::to_string(data)?;
     let headers = Headers::new();
@@ -0,0 +1,47 @@
+//! Server-issued session tokens
+//!
+//! A successful OPAQUE login mints an opaque (to the client) bearer token and
+//! stores `session:{token} -> client_identifier` in the `SESSIONS` KV with a
+//! fixed TTL. Routes that require authentication resolve the token back to a
+//! `client_identifier` via [`lookup_session`]; `routes::authenticate` wraps
+//! that lookup with the Bearer-header parsing and 401 handling.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use worker::*;
+
+/// Session lifetime. Cloudflare KV TTL enforces expiry server-side.
+pub const SESSION_TTL_SECONDS: u64 = 60 * 60 * 24; // 24h
+
+fn storage_key(token: &str) -> String {
+    format!("session:{}", token)
+}
+
+/// Mint a new session token for `client_identifier` and persist it.
+pub async fn create_session(env: &Env, client_identifier: &str) -> Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = BASE64URL.encode(bytes);
+
+    let sessions = env.kv("SESSIONS")?;
+    sessions
+        .put(&storage_key(&token), client_identifier)?
+        .expiration_ttl(SESSION_TTL_SECONDS)
+        .execute()
+        .await?;
+
+    Ok(token)
+}
+
+/// Resolve a session token to its `client_identifier`, or `None` if missing/expired.
+pub async fn lookup_session(env: &Env, token: &str) -> Result<Option<String>> {
+    let sessions = env.kv("SESSIONS")?;
+    sessions.get(&storage_key(token)).text().await
+}
+
+/// Invalidate a session token (used by logout).
+pub async fn delete_session(env: &Env, token: &str) -> Result<()> {
+    let sessions = env.kv("SESSIONS")?;
+    sessions.delete(&storage_key(token)).await
+}
@@ -1,10 +1,15 @@
+use argon2::{Algorithm, Argon2, Params as Argon2InternalParams, Version};
+use hkdf::Hkdf;
 use opaque_ke::{
     rand::rngs::OsRng, CipherSuite, ClientLogin as OpaqueClientLogin, ClientLoginFinishParameters,
-    ClientRegistration as OpaqueClientRegistration, ClientRegistrationFinishParameters, CredentialResponse,
-    RegistrationResponse, Ristretto255,
+    ClientRegistration as OpaqueClientRegistration, ClientRegistrationFinishParameters, CredentialFinalization,
+    CredentialRequest, CredentialResponse, Identifiers, RegistrationRequest, RegistrationResponse, RegistrationUpload,
+    Ristretto255, ServerLogin as OpaqueServerLogin, ServerLoginParameters, ServerRegistration as OpaqueServerRegistration,
+    ServerSetup as OpaqueServerSetup,
 };
 use sha2::{Digest, Sha256, Sha512};
 use std::sync::Mutex;
+use zeroize::Zeroizing;
 
 uniffi::setup_scaffolding!();
 
@@ -18,6 +23,59 @@ impl CipherSuite for DefaultCipherSuite {
     type Ksf = argon2::Argon2<'static>;
 }
 
+/// Argon2 key-stretching parameters for hardening the password prior to the OPRF step.
+/// Must match the backend worker's configured cost or envelope derivation will produce
+/// a different key and login will fail with a valid password.
+#[derive(uniffi::Record)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    fn build(&self) -> Result<Argon2<'static>, OpaqueError> {
+        let params = Argon2InternalParams::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|_| OpaqueError::InvalidInput)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+/// Build the `Identifiers` binding the OPAQUE transcript to application-level identities.
+/// Both sides of a handshake must supply matching identifiers or the finalization fails
+/// to verify - this is what stops credential confusion when one device holds several
+/// family members' accounts against the same server.
+fn identifiers<'a>(client_identity: &'a Option<Vec<u8>>, server_identity: &'a Option<Vec<u8>>) -> Identifiers<'a> {
+    Identifiers {
+        client: client_identity.as_deref(),
+        server: server_identity.as_deref(),
+    }
+}
+
+/// Pack a persisted client handshake as a 4-byte big-endian length prefix for `request`
+/// followed by `request` followed by the raw opaque-ke state bytes, so `export_state`/
+/// `restore` can round-trip both halves through a single opaque blob without guessing
+/// where one serialization ends and the other begins.
+fn pack_state(request: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(4 + request.len() + state.len());
+    packed.extend_from_slice(&(request.len() as u32).to_be_bytes());
+    packed.extend_from_slice(request);
+    packed.extend_from_slice(state);
+    packed
+}
+
+fn unpack_state(bytes: &[u8]) -> Result<(&[u8], &[u8]), OpaqueError> {
+    if bytes.len() < 4 {
+        return Err(OpaqueError::SerializationError);
+    }
+    let (len_bytes, rest) = bytes.split_at(4);
+    let request_len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+    if rest.len() < request_len {
+        return Err(OpaqueError::SerializationError);
+    }
+    Ok(rest.split_at(request_len))
+}
+
 #[derive(Debug, thiserror::Error, uniffi::Error)]
 pub enum OpaqueError {
     #[error("Protocol error")]
@@ -41,6 +99,35 @@ pub fn generate_client_identifier(username: String) -> Result<String, OpaqueErro
     Ok(hex::encode(result))
 }
 
+/// Largest length `Hkdf::<Sha512>::expand` can produce (255 * the hash output length).
+const MAX_DERIVED_KEY_LEN: u32 = 255 * 64;
+
+/// Derive a data-encryption key from an OPAQUE `export_key` via HKDF-SHA512, so callers
+/// get a vetted KDF instead of truncating the raw export key themselves. `info` domain-
+/// separates independent subkeys drawn from the same authentication secret.
+#[uniffi::export]
+pub fn derive_key(export_key: Vec<u8>, info: String, length: u32) -> Result<Vec<u8>, OpaqueError> {
+    if length > MAX_DERIVED_KEY_LEN {
+        return Err(OpaqueError::InvalidInput);
+    }
+
+    let hk = Hkdf::<Sha512>::new(None, &export_key);
+    let mut output = vec![0u8; length as usize];
+    hk.expand(info.as_bytes(), &mut output)
+        .map_err(|_| OpaqueError::InvalidInput)?;
+
+    Ok(output)
+}
+
+const RECORD_KEY_INFO_PREFIX: &str = "family-medical-app-record-key-v1:";
+
+/// Convenience wrapper over `derive_key` that fixes the `info` label to a per-record
+/// namespace, so different medical records get independent 32-byte subkeys.
+#[uniffi::export]
+pub fn derive_record_key(export_key: Vec<u8>, record_id: String) -> Result<Vec<u8>, OpaqueError> {
+    derive_key(export_key, format!("{}{}", RECORD_KEY_INFO_PREFIX, record_id), 32)
+}
+
 #[derive(uniffi::Record)]
 pub struct RegistrationResult {
     pub registration_upload: Vec<u8>,
@@ -54,23 +141,27 @@ pub struct LoginResult {
     pub export_key: Vec<u8>,
 }
 
-/// Client registration state wrapper
+/// Client registration state wrapper. `request` is retained for the lifetime of the
+/// object so `get_request()` can be called repeatedly; it's wrapped in `Zeroizing` so a
+/// leaked/long-lived object doesn't keep protocol transcript bytes around any longer
+/// than it has to.
 #[derive(uniffi::Object)]
 pub struct ClientRegistration {
     state: Mutex<Option<OpaqueClientRegistration<DefaultCipherSuite>>>,
-    request: Vec<u8>,
+    request: Zeroizing<Vec<u8>>,
 }
 
 #[uniffi::export]
 impl ClientRegistration {
     #[uniffi::constructor]
     pub fn start(password: String) -> Result<Self, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut rng = OsRng;
 
         let result = OpaqueClientRegistration::<DefaultCipherSuite>::start(&mut rng, password.as_bytes())
             .map_err(|_| OpaqueError::ProtocolError)?;
 
-        let request = result.message.serialize().to_vec();
+        let request = Zeroizing::new(result.message.serialize().to_vec());
 
         Ok(Self {
             state: Mutex::new(Some(result.state)),
@@ -80,12 +171,13 @@ impl ClientRegistration {
 
     #[uniffi::constructor]
     pub fn start_with_bytes(password: Vec<u8>) -> Result<Self, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut rng = OsRng;
 
         let result = OpaqueClientRegistration::<DefaultCipherSuite>::start(&mut rng, &password)
             .map_err(|_| OpaqueError::ProtocolError)?;
 
-        let request = result.message.serialize().to_vec();
+        let request = Zeroizing::new(result.message.serialize().to_vec());
 
         Ok(Self {
             state: Mutex::new(Some(result.state)),
@@ -94,29 +186,69 @@ impl ClientRegistration {
     }
 
     pub fn get_request(&self) -> Vec<u8> {
-        self.request.clone()
+        self.request.to_vec()
+    }
+
+    /// Persist this in-flight registration so it can survive the process being
+    /// suspended between `start` and `finish` (e.g. the app is backgrounded mid-
+    /// handshake on iOS/Android). Store the result in secure storage and pass it to
+    /// `restore` to pick the handshake back up; it must not be reused across two
+    /// concurrent `finish` calls, same as the object itself.
+    pub fn export_state(&self) -> Result<Vec<u8>, OpaqueError> {
+        let state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
+        let state = state_guard.as_ref().ok_or(OpaqueError::ProtocolError)?;
+        Ok(pack_state(&self.request, &state.serialize()))
     }
 
-    pub fn finish(&self, server_response: Vec<u8>, password: String) -> Result<RegistrationResult, OpaqueError> {
+    #[uniffi::constructor]
+    pub fn restore(state_bytes: Vec<u8>) -> Result<Self, OpaqueError> {
+        let (request, state_bytes) = unpack_state(&state_bytes)?;
+        let state = OpaqueClientRegistration::<DefaultCipherSuite>::deserialize(state_bytes)
+            .map_err(|_| OpaqueError::SerializationError)?;
+
+        Ok(Self {
+            state: Mutex::new(Some(state)),
+            request: Zeroizing::new(request.to_vec()),
+        })
+    }
+
+    pub fn finish(
+        &self,
+        server_response: Vec<u8>,
+        password: String,
+        argon2_params: Option<Argon2Params>,
+        client_identity: Option<Vec<u8>>,
+        server_identity: Option<Vec<u8>>,
+    ) -> Result<RegistrationResult, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
         let state = state_guard.take().ok_or(OpaqueError::ProtocolError)?;
 
         let response =
             RegistrationResponse::deserialize(&server_response).map_err(|_| OpaqueError::SerializationError)?;
 
+        let ksf = argon2_params.as_ref().map(Argon2Params::build).transpose()?;
         let mut rng = OsRng;
         let result = state
             .finish(
                 &mut rng,
                 password.as_bytes(),
                 response,
-                ClientRegistrationFinishParameters::default(),
+                ClientRegistrationFinishParameters::new(
+                    identifiers(&client_identity, &server_identity),
+                    ksf.as_ref(),
+                ),
             )
             .map_err(|_| OpaqueError::ProtocolError)?;
 
+        // `export_key` can't be returned as a Zeroizing-backed type - uniffi::Record
+        // fields must be plain FFI-safe types - so this copy necessarily outlives our
+        // control once it crosses into Swift. Keeping it in a Zeroizing wrapper here at
+        // least scrubs the Rust-side intermediate rather than leaving two live copies.
+        let export_key = Zeroizing::new(result.export_key.to_vec());
         Ok(RegistrationResult {
             registration_upload: result.message.serialize().to_vec(),
-            export_key: result.export_key.to_vec(),
+            export_key: export_key.to_vec(),
         })
     }
 
@@ -124,47 +256,58 @@ impl ClientRegistration {
         &self,
         server_response: Vec<u8>,
         password: Vec<u8>,
+        argon2_params: Option<Argon2Params>,
+        client_identity: Option<Vec<u8>>,
+        server_identity: Option<Vec<u8>>,
     ) -> Result<RegistrationResult, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
         let state = state_guard.take().ok_or(OpaqueError::ProtocolError)?;
 
         let response =
             RegistrationResponse::deserialize(&server_response).map_err(|_| OpaqueError::SerializationError)?;
 
+        let ksf = argon2_params.as_ref().map(Argon2Params::build).transpose()?;
         let mut rng = OsRng;
         let result = state
             .finish(
                 &mut rng,
                 &password,
                 response,
-                ClientRegistrationFinishParameters::default(),
+                ClientRegistrationFinishParameters::new(
+                    identifiers(&client_identity, &server_identity),
+                    ksf.as_ref(),
+                ),
             )
             .map_err(|_| OpaqueError::ProtocolError)?;
 
+        let export_key = Zeroizing::new(result.export_key.to_vec());
         Ok(RegistrationResult {
             registration_upload: result.message.serialize().to_vec(),
-            export_key: result.export_key.to_vec(),
+            export_key: export_key.to_vec(),
         })
     }
 }
 
-/// Client login state wrapper
+/// Client login state wrapper. `request` is wrapped in `Zeroizing` for the same reason
+/// as `ClientRegistration::request`.
 #[derive(uniffi::Object)]
 pub struct ClientLogin {
     state: Mutex<Option<OpaqueClientLogin<DefaultCipherSuite>>>,
-    request: Vec<u8>,
+    request: Zeroizing<Vec<u8>>,
 }
 
 #[uniffi::export]
 impl ClientLogin {
     #[uniffi::constructor]
     pub fn start(password: String) -> Result<Self, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut rng = OsRng;
 
         let result = OpaqueClientLogin::<DefaultCipherSuite>::start(&mut rng, password.as_bytes())
             .map_err(|_| OpaqueError::ProtocolError)?;
 
-        let request = result.message.serialize().to_vec();
+        let request = Zeroizing::new(result.message.serialize().to_vec());
 
         Ok(Self {
             state: Mutex::new(Some(result.state)),
@@ -174,12 +317,13 @@ impl ClientLogin {
 
     #[uniffi::constructor]
     pub fn start_with_bytes(password: Vec<u8>) -> Result<Self, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut rng = OsRng;
 
         let result = OpaqueClientLogin::<DefaultCipherSuite>::start(&mut rng, &password)
             .map_err(|_| OpaqueError::ProtocolError)?;
 
-        let request = result.message.serialize().to_vec();
+        let request = Zeroizing::new(result.message.serialize().to_vec());
 
         Ok(Self {
             state: Mutex::new(Some(result.state)),
@@ -188,49 +332,260 @@ impl ClientLogin {
     }
 
     pub fn get_request(&self) -> Vec<u8> {
-        self.request.clone()
+        self.request.to_vec()
+    }
+
+    /// Persist this in-flight login so it can survive the process being suspended
+    /// between `start` and `finish`, same as `ClientRegistration::export_state`.
+    pub fn export_state(&self) -> Result<Vec<u8>, OpaqueError> {
+        let state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
+        let state = state_guard.as_ref().ok_or(OpaqueError::ProtocolError)?;
+        Ok(pack_state(&self.request, &state.serialize()))
+    }
+
+    #[uniffi::constructor]
+    pub fn restore(state_bytes: Vec<u8>) -> Result<Self, OpaqueError> {
+        let (request, state_bytes) = unpack_state(&state_bytes)?;
+        let state = OpaqueClientLogin::<DefaultCipherSuite>::deserialize(state_bytes)
+            .map_err(|_| OpaqueError::SerializationError)?;
+
+        Ok(Self {
+            state: Mutex::new(Some(state)),
+            request: Zeroizing::new(request.to_vec()),
+        })
     }
 
-    pub fn finish(&self, server_response: Vec<u8>, password: String) -> Result<LoginResult, OpaqueError> {
+    pub fn finish(
+        &self,
+        server_response: Vec<u8>,
+        password: String,
+        argon2_params: Option<Argon2Params>,
+        client_identity: Option<Vec<u8>>,
+        server_identity: Option<Vec<u8>>,
+    ) -> Result<LoginResult, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
         let state = state_guard.take().ok_or(OpaqueError::ProtocolError)?;
 
         let response =
             CredentialResponse::deserialize(&server_response).map_err(|_| OpaqueError::SerializationError)?;
 
+        let ksf = argon2_params.as_ref().map(Argon2Params::build).transpose()?;
         let mut rng = OsRng;
         let result = state
             .finish(
                 &mut rng,
                 password.as_bytes(),
                 response,
-                ClientLoginFinishParameters::default(),
+                ClientLoginFinishParameters::new(identifiers(&client_identity, &server_identity), ksf.as_ref(), None),
             )
             .map_err(|_| OpaqueError::ProtocolError)?;
 
+        // See the comment on `ClientRegistration::finish` about why these can't be
+        // returned as Zeroizing-backed types themselves.
+        let session_key = Zeroizing::new(result.session_key.to_vec());
+        let export_key = Zeroizing::new(result.export_key.to_vec());
         Ok(LoginResult {
             credential_finalization: result.message.serialize().to_vec(),
-            session_key: result.session_key.to_vec(),
-            export_key: result.export_key.to_vec(),
+            session_key: session_key.to_vec(),
+            export_key: export_key.to_vec(),
         })
     }
 
-    pub fn finish_with_bytes(&self, server_response: Vec<u8>, password: Vec<u8>) -> Result<LoginResult, OpaqueError> {
+    pub fn finish_with_bytes(
+        &self,
+        server_response: Vec<u8>,
+        password: Vec<u8>,
+        argon2_params: Option<Argon2Params>,
+        client_identity: Option<Vec<u8>>,
+        server_identity: Option<Vec<u8>>,
+    ) -> Result<LoginResult, OpaqueError> {
+        let password = Zeroizing::new(password);
         let mut state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
         let state = state_guard.take().ok_or(OpaqueError::ProtocolError)?;
 
         let response =
             CredentialResponse::deserialize(&server_response).map_err(|_| OpaqueError::SerializationError)?;
 
+        let ksf = argon2_params.as_ref().map(Argon2Params::build).transpose()?;
         let mut rng = OsRng;
         let result = state
-            .finish(&mut rng, &password, response, ClientLoginFinishParameters::default())
+            .finish(
+                &mut rng,
+                &password,
+                response,
+                ClientLoginFinishParameters::new(identifiers(&client_identity, &server_identity), ksf.as_ref(), None),
+            )
             .map_err(|_| OpaqueError::ProtocolError)?;
 
+        let session_key = Zeroizing::new(result.session_key.to_vec());
+        let export_key = Zeroizing::new(result.export_key.to_vec());
         Ok(LoginResult {
             credential_finalization: result.message.serialize().to_vec(),
-            session_key: result.session_key.to_vec(),
-            export_key: result.export_key.to_vec(),
+            session_key: session_key.to_vec(),
+            export_key: export_key.to_vec(),
+        })
+    }
+}
+
+/// Server-side long-term key material (keypair + OPRF seed). Persist `serialize()`'d
+/// bytes and reload via `from_bytes` rather than generating a new setup per request -
+/// every stored `RegistrationUpload` is bound to the setup it was registered under.
+#[derive(uniffi::Object)]
+pub struct ServerSetup {
+    inner: OpaqueServerSetup<DefaultCipherSuite>,
+}
+
+#[uniffi::export]
+impl ServerSetup {
+    #[uniffi::constructor]
+    pub fn new() -> Self {
+        let mut rng = OsRng;
+        Self {
+            inner: OpaqueServerSetup::<DefaultCipherSuite>::new(&mut rng),
+        }
+    }
+
+    #[uniffi::constructor]
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, OpaqueError> {
+        let inner = OpaqueServerSetup::<DefaultCipherSuite>::deserialize(&bytes)
+            .map_err(|_| OpaqueError::SerializationError)?;
+        Ok(Self { inner })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.inner.serialize().to_vec()
+    }
+}
+
+impl Default for ServerSetup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Server-side registration wrapper. `start` processes the client's registration
+/// request; `finish` takes the client's `RegistrationUpload` and yields the bytes to
+/// store as that credential's password file.
+#[derive(uniffi::Object)]
+pub struct ServerRegistration {
+    registration_response: Vec<u8>,
+}
+
+#[uniffi::export]
+impl ServerRegistration {
+    #[uniffi::constructor]
+    pub fn start(setup: &ServerSetup, registration_request: Vec<u8>, credential_identifier: Vec<u8>) -> Result<Self, OpaqueError> {
+        let request = RegistrationRequest::<DefaultCipherSuite>::deserialize(&registration_request)
+            .map_err(|_| OpaqueError::SerializationError)?;
+
+        let result = OpaqueServerRegistration::<DefaultCipherSuite>::start(&setup.inner, request, &credential_identifier)
+            .map_err(|_| OpaqueError::ProtocolError)?;
+
+        Ok(Self {
+            registration_response: result.message.serialize().to_vec(),
+        })
+    }
+
+    pub fn get_registration_response(&self) -> Vec<u8> {
+        self.registration_response.clone()
+    }
+
+    pub fn finish(&self, registration_upload: Vec<u8>) -> Result<Vec<u8>, OpaqueError> {
+        let upload = RegistrationUpload::<DefaultCipherSuite>::deserialize(&registration_upload)
+            .map_err(|_| OpaqueError::SerializationError)?;
+
+        let server_registration = OpaqueServerRegistration::<DefaultCipherSuite>::finish(upload);
+
+        Ok(server_registration.serialize().to_vec())
+    }
+}
+
+#[derive(uniffi::Record)]
+pub struct ServerLoginResult {
+    pub session_key: Vec<u8>,
+}
+
+/// Server-side login wrapper. `start` is given `password_file = None` for unknown
+/// credential identifiers so it emits a well-formed dummy response, defeating
+/// account-enumeration the same way the lldap OPAQUE server does.
+#[derive(uniffi::Object)]
+pub struct ServerLogin {
+    state: Mutex<Option<OpaqueServerLogin<DefaultCipherSuite>>>,
+    credential_response: Vec<u8>,
+    client_identity: Option<Vec<u8>>,
+    server_identity: Option<Vec<u8>>,
+}
+
+#[uniffi::export]
+impl ServerLogin {
+    #[uniffi::constructor]
+    pub fn start(
+        setup: &ServerSetup,
+        password_file: Option<Vec<u8>>,
+        credential_request: Vec<u8>,
+        credential_identifier: Vec<u8>,
+        client_identity: Option<Vec<u8>>,
+        server_identity: Option<Vec<u8>>,
+    ) -> Result<Self, OpaqueError> {
+        let request = CredentialRequest::<DefaultCipherSuite>::deserialize(&credential_request)
+            .map_err(|_| OpaqueError::SerializationError)?;
+
+        let password_file = password_file.map(Zeroizing::new);
+        let server_registration = match &password_file {
+            Some(bytes) => Some(
+                OpaqueServerRegistration::<DefaultCipherSuite>::deserialize(bytes)
+                    .map_err(|_| OpaqueError::SerializationError)?,
+            ),
+            None => None,
+        };
+
+        let mut rng = OsRng;
+        let result = OpaqueServerLogin::<DefaultCipherSuite>::start(
+            &mut rng,
+            &setup.inner,
+            server_registration,
+            request,
+            &credential_identifier,
+            ServerLoginParameters {
+                identifiers: identifiers(&client_identity, &server_identity),
+                context: None,
+            },
+        )
+        .map_err(|_| OpaqueError::ProtocolError)?;
+
+        Ok(Self {
+            state: Mutex::new(Some(result.state)),
+            credential_response: result.message.serialize().to_vec(),
+            client_identity,
+            server_identity,
+        })
+    }
+
+    pub fn get_credential_response(&self) -> Vec<u8> {
+        self.credential_response.clone()
+    }
+
+    pub fn finish(&self, credential_finalization: Vec<u8>) -> Result<ServerLoginResult, OpaqueError> {
+        let mut state_guard = self.state.lock().map_err(|_| OpaqueError::ProtocolError)?;
+        let state = state_guard.take().ok_or(OpaqueError::ProtocolError)?;
+
+        let finalization = CredentialFinalization::<DefaultCipherSuite>::deserialize(&credential_finalization)
+            .map_err(|_| OpaqueError::SerializationError)?;
+
+        let result = state
+            .finish(
+                finalization,
+                ServerLoginParameters {
+                    identifiers: identifiers(&self.client_identity, &self.server_identity),
+                    context: None,
+                },
+            )
+            .map_err(|_| OpaqueError::ProtocolError)?;
+
+        let session_key = Zeroizing::new(result.session_key.to_vec());
+        Ok(ServerLoginResult {
+            session_key: session_key.to_vec(),
         })
     }
 }
@@ -258,4 +613,75 @@ mod tests {
         let request = login.get_request();
         assert!(!request.is_empty());
     }
+
+    #[test]
+    fn test_server_registration_and_login_round_trip() {
+        let password = b"test-password-123".to_vec();
+        let credential_identifier = b"user-1".to_vec();
+        let setup = ServerSetup::new();
+
+        let client_reg = ClientRegistration::start_with_bytes(password.clone()).unwrap();
+        let server_reg = ServerRegistration::start(&setup, client_reg.get_request(), credential_identifier.clone()).unwrap();
+        let registration_result = client_reg
+            .finish_with_bytes(server_reg.get_registration_response(), password.clone(), None, None, None)
+            .unwrap();
+        let password_file = server_reg.finish(registration_result.registration_upload).unwrap();
+
+        let client_login = ClientLogin::start_with_bytes(password.clone()).unwrap();
+        let server_login = ServerLogin::start(
+            &setup,
+            Some(password_file),
+            client_login.get_request(),
+            credential_identifier,
+            None,
+            None,
+        )
+        .unwrap();
+        let login_result = client_login
+            .finish_with_bytes(server_login.get_credential_response(), password, None, None, None)
+            .unwrap();
+        let server_result = server_login.finish(login_result.credential_finalization).unwrap();
+
+        assert_eq!(server_result.session_key, login_result.session_key);
+    }
+
+    #[test]
+    fn test_derive_record_key_is_deterministic_and_namespaced() {
+        let export_key = b"0123456789abcdef0123456789abcdef".to_vec();
+
+        let key_a = derive_record_key(export_key.clone(), "record-1".to_string()).unwrap();
+        let key_a_again = derive_record_key(export_key.clone(), "record-1".to_string()).unwrap();
+        let key_b = derive_record_key(export_key, "record-2".to_string()).unwrap();
+
+        assert_eq!(key_a.len(), 32);
+        assert_eq!(key_a, key_a_again);
+        assert_ne!(key_a, key_b);
+    }
+
+    #[test]
+    fn test_derive_key_rejects_too_long_output() {
+        let result = derive_key(b"export-key".to_vec(), "info".to_string(), MAX_DERIVED_KEY_LEN + 1);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_client_registration_survives_export_and_restore() {
+        let password = b"test-password-123".to_vec();
+        let credential_identifier = b"user-1".to_vec();
+        let setup = ServerSetup::new();
+
+        let client_reg = ClientRegistration::start_with_bytes(password.clone()).unwrap();
+        let request = client_reg.get_request();
+        let exported = client_reg.export_state().unwrap();
+        drop(client_reg);
+
+        let restored = ClientRegistration::restore(exported).unwrap();
+        assert_eq!(restored.get_request(), request);
+
+        let server_reg = ServerRegistration::start(&setup, restored.get_request(), credential_identifier).unwrap();
+        let result = restored
+            .finish_with_bytes(server_reg.get_registration_response(), password, None, None, None)
+            .unwrap();
+        assert!(!result.registration_upload.is_empty());
+    }
 }